@@ -12,22 +12,43 @@ mod tests {
     use defmt::assert;
     use nrf52840_hal::gpio::{PushPull, Output, Pin};
 
+    /// Test signal pins D0-D2 of the logic port, plus the index of the test
+    /// about to run. `before_each`/`after_each` drive the pins with the
+    /// binary representation of `test_index`, so powertest can attribute
+    /// measurements to the right test even if it misses an edge.
+    struct State {
+        test_signal_pins: [Pin<Output<PushPull>>; 3],
+        test_index: u8,
+    }
+
     #[init]
-    fn init() -> Pin<Output<PushPull>> {
+    fn init() -> State {
         let p = pac::Peripherals::take().unwrap();
         let port0 = hal::gpio::p0::Parts::new(p.P0);
-        // Initially set test signal pin to high. 
-        // Powertest will start measuring on the first
-        // high-to-low transition of the pin.
-        let test_signal_pin = port0.p0_03.into_push_pull_output(Level::High).degrade();
-        cortex_m::asm::delay(64_000_000); 
-        test_signal_pin
+        // Initially set all test signal pins high. This is the reserved
+        // "idle" code: powertest discards samples seen while it is active.
+        let test_signal_pins = [
+            port0.p0_03.into_push_pull_output(Level::High).degrade(),
+            port0.p0_04.into_push_pull_output(Level::High).degrade(),
+            port0.p0_05.into_push_pull_output(Level::High).degrade(),
+        ];
+        cortex_m::asm::delay(64_000_000);
+        State {
+            test_signal_pins,
+            test_index: 0,
+        }
     }
 
     #[before_each]
-    fn before_each(test_signal_pin: &mut Pin<Output<PushPull>>) {
-        // Set pin low to signal that a test has started
-        test_signal_pin.set_low().unwrap();
+    fn before_each(state: &mut State) {
+        // Drive D0-D2 with the binary representation of the test about to
+        // run, so powertest can attribute the coming samples to it.
+        for (bit, pin) in state.test_signal_pins.iter_mut().enumerate() {
+            match state.test_index & (1 << bit) {
+                0 => pin.set_low().unwrap(),
+                _ => pin.set_high().unwrap(),
+            }
+        }
         // As this delay affects measurements,
         // it should be as short as possible, though long enough
         // for powertest to detect it.
@@ -35,13 +56,16 @@ mod tests {
     }
 
     #[after_each]
-    fn after_each(test_signal_pin: &mut Pin<Output<PushPull>>) {
-        // Set pin high to signal that a test has stopped
-        test_signal_pin.set_high().unwrap();
-        // Measurements are ignored if pin is high,
-        // so the length of this delay does not affect
-        // measurement data
+    fn after_each(state: &mut State) {
+        // Set all pins high to signal the idle code: the test has stopped,
+        // and powertest should discard samples until the next test starts.
+        for pin in state.test_signal_pins.iter_mut() {
+            pin.set_high().unwrap();
+        }
+        // Measurements are ignored while idle, so the length of this delay
+        // does not affect measurement data.
         cortex_m::asm::delay(64000000);
+        state.test_index += 1;
     }
 
     #[test]
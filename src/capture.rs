@@ -0,0 +1,59 @@
+//! Streams every raw measurement to disk for offline waveform analysis, so
+//! transients that per-test averaging hides (e.g. spikes at test
+//! boundaries) can still be inspected.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+};
+
+use anyhow::Result;
+use ppk2::types::{Level, LogicPortPins};
+
+/// One raw sample, as received from the PPK2, destined for the capture file.
+pub struct CaptureSample {
+    pub timestamp_secs: f32,
+    pub micro_amps: f32,
+    pub pins: LogicPortPins,
+}
+
+/// Create `path` and spawn a dedicated writer thread that appends every
+/// [`CaptureSample`] sent over the returned channel to it as CSV. Writing
+/// happens entirely on that thread, so disk I/O never stalls the caller's
+/// measurement matcher.
+pub fn spawn(path: impl AsRef<Path>) -> Result<(mpsc::Sender<CaptureSample>, JoinHandle<Result<()>>)> {
+    let mut writer = BufWriter::new(File::create(path.as_ref())?);
+    writeln!(writer, "timestamp_secs,micro_amps,pins")?;
+
+    let (tx, rx) = mpsc::channel::<CaptureSample>();
+    let handle = thread::spawn(move || -> Result<()> {
+        for sample in rx {
+            writeln!(
+                writer,
+                "{:.6},{},{}",
+                sample.timestamp_secs,
+                sample.micro_amps,
+                format_pins(sample.pins)
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    });
+    Ok((tx, handle))
+}
+
+/// Render the logic port as one `H`/`L`/`E` character per pin, comma-free so
+/// it fits in a single CSV column regardless of the crate's `Debug` format.
+fn format_pins(pins: LogicPortPins) -> String {
+    pins.to_levels()
+        .iter()
+        .map(|level| match level {
+            Level::High => 'H',
+            Level::Low => 'L',
+            Level::Either => 'E',
+        })
+        .collect()
+}
@@ -0,0 +1,149 @@
+//! Decodes the `defmt`/RTT stream `defmt_test` emits while the firmware
+//! runs, so power reports can be labeled with the actual test name and
+//! pass/fail result instead of an anonymous index.
+
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use defmt_decoder::{DecodeError, Table};
+use probe_rs::{rtt::Rtt, Session};
+use tracing::{trace, warn};
+
+/// A lifecycle event decoded from the RTT stream. `defmt_test` 0.3 names a
+/// test only when it starts; it has no per-test completion line, so whether
+/// a test passed is known only once the whole suite finishes (or panics).
+#[derive(Debug, Clone)]
+pub enum TestEvent {
+    /// `defmt_test` printed "(i/n) running `<name>`...".
+    Started { test_name: String },
+    /// `defmt_test` printed "all tests passed!" once every test in the suite
+    /// completed successfully.
+    AllPassed,
+    /// The firmware panicked, decoded from a `"panicked at ..."` frame.
+    /// `defmt_test` aborts the whole run on a panic, so this marks every
+    /// test that has started so far (and none that hasn't) as failed.
+    Panicked { message: String },
+}
+
+/// Attach to the RTT channel `defmt_test` logs to, and decode its frames
+/// using the `.defmt` table embedded in `elf_bytes` against `session`.
+/// Runs the RTT reader on its own thread so decoding never blocks the
+/// measurement loop; decoded events are forwarded over the returned
+/// channel in the order they're logged by the firmware.
+pub fn spawn(session: Arc<Mutex<Session>>, elf_bytes: Vec<u8>) -> Result<mpsc::Receiver<TestEvent>> {
+    let table = Table::parse(&elf_bytes)?.context("ELF has no `.defmt` section")?;
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        if let Err(e) = read_loop(session, table, tx) {
+            warn!("defmt/RTT reader stopped: {e}");
+        }
+    });
+    Ok(rx)
+}
+
+fn read_loop(session: Arc<Mutex<Session>>, table: Table, tx: mpsc::Sender<TestEvent>) -> Result<()> {
+    let mut channel = {
+        let mut session = session.lock().unwrap();
+        let memory_map = session.target().memory_map.clone();
+        let mut core = session.core(0)?;
+        let mut rtt = Rtt::attach(&mut core, &memory_map)?;
+        rtt.up_channels()
+            .take(0)
+            .context("firmware does not expose an RTT up channel")?
+    };
+
+    let mut decoder = table.new_stream_decoder();
+    let mut buf = [0u8; 1024];
+    loop {
+        let count = {
+            let mut session = session.lock().unwrap();
+            let mut core = session.core(0)?;
+            channel.read(&mut core, &mut buf)?
+        };
+        if count == 0 {
+            thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+        decoder.received(&buf[..count]);
+        loop {
+            match decoder.decode() {
+                Ok(frame) => {
+                    let line = frame.display_message().to_string();
+                    trace!("defmt: {line}");
+                    if let Some(event) = parse_test_event(&line) {
+                        if tx.send(event).is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+                Err(DecodeError::UnexpectedEof) => break,
+                Err(DecodeError::Malformed(e)) => {
+                    warn!("Malformed defmt frame, resyncing: {e:?}");
+                }
+            }
+        }
+    }
+}
+
+/// Parse a decoded defmt line emitted by `defmt_test` 0.3 into a
+/// [`TestEvent`]. It logs `"(i/n) running \`<name>\`..."` when a test
+/// starts and `"all tests passed!"` once the whole suite finishes; there is
+/// no per-test completion line, and a failing test panics instead of
+/// logging a result.
+fn parse_test_event(line: &str) -> Option<TestEvent> {
+    if let Some(rest) = line.split("running `").nth(1) {
+        let name = rest.split('`').next()?;
+        return Some(TestEvent::Started {
+            test_name: name.to_string(),
+        });
+    }
+    if line.trim() == "all tests passed!" {
+        return Some(TestEvent::AllPassed);
+    }
+    let message = line.strip_prefix("panicked at ")?;
+    Some(TestEvent::Panicked {
+        message: message.trim().to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_running_test_line() {
+        let event = parse_test_event("(1/3) running `it_works`...").unwrap();
+        assert!(matches!(event, TestEvent::Started { test_name } if test_name == "it_works"));
+    }
+
+    #[test]
+    fn parses_all_tests_passed_line() {
+        let event = parse_test_event("all tests passed!").unwrap();
+        assert!(matches!(event, TestEvent::AllPassed));
+    }
+
+    #[test]
+    fn parses_panic_line() {
+        let event = parse_test_event("panicked at 'assertion failed', tests/power.rs:42:9").unwrap();
+        assert!(
+            matches!(event, TestEvent::Panicked { message } if message.starts_with("'assertion failed'"))
+        );
+    }
+
+    #[test]
+    fn ignores_unrelated_lines() {
+        assert!(parse_test_event("some other log line").is_none());
+    }
+
+    #[test]
+    fn does_not_match_libtest_format() {
+        // The libtest/`cargo test` format this firmware does NOT use.
+        assert!(parse_test_event("running test it_works").is_none());
+        assert!(parse_test_event("test it_works ... ok").is_none());
+    }
+}
@@ -1,14 +1,20 @@
+mod capture;
+mod defmt_stream;
+
 use std::{
+    collections::{HashMap, VecDeque},
     path::{Path, PathBuf},
     sync::{mpsc::RecvTimeoutError, Arc, Mutex},
     time::Duration,
 };
 
 use anyhow::{bail, Result};
+use capture::CaptureSample;
 use clap::Parser;
+use defmt_stream::TestEvent;
 use ppk2::{
     measurement::{Measurement, MeasurementMatch},
-    types::{DevicePower, Level as PinLevel, LogicPortPins, MeasurementMode, SourceVoltage},
+    types::{DevicePower, Level as PinLevel, LogicPortPins, MeasurementMode, SourceVoltage, SPS_MAX},
     Ppk2,
 };
 use probe_rs::{
@@ -61,6 +67,62 @@ struct Args {
         default_value = "1000"
     )]
     sps: usize,
+
+    #[arg(
+        short = 'f',
+        long,
+        value_enum,
+        help = "Output format for the machine-readable per-test report, written once the run completes",
+        default_value = "text"
+    )]
+    format: OutputFormat,
+
+    #[arg(
+        short = 'o',
+        long,
+        help = "Path to write the --format report to. Defaults to stdout for json/csv; ignored for text, since that is already logged live"
+    )]
+    output: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Maximum mean current, in µA, any single test may draw. A test exceeding this fails the run (non-zero exit code). Overridden per-test by --budget-file"
+    )]
+    budget: Option<f32>,
+
+    #[arg(
+        long,
+        help = "Path to a JSON file mapping test name to a per-test µA budget, taking precedence over --budget for the tests it lists"
+    )]
+    budget_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Path to a previously exported --format json report. Tests present in both runs are compared, and a regression beyond --regression-threshold fails the run"
+    )]
+    baseline: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Maximum allowed increase in mean current vs --baseline, as a percentage",
+        default_value = "10.0"
+    )]
+    regression_threshold: f32,
+
+    #[arg(
+        long,
+        help = "Stream every raw sample (timestamp, µA, logic port bits) to this CSV file, for inspecting transients averaging hides. Implies sampling at SPS_MAX"
+    )]
+    capture: Option<PathBuf>,
+}
+
+/// Machine-readable report format, for archiving and diffing results in CI.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    /// No separate report; per-test results are only logged live.
+    Text,
+    Json,
+    Csv,
 }
 
 fn main() -> Result<()> {
@@ -75,6 +137,23 @@ fn main() -> Result<()> {
         Some(n) => n,
         None => read_test_count(&args.elf)?,
     };
+    // Index 0..MAX_TEST_COUNT is representable on the logic port; the
+    // all-high code is reserved to mean "idle", not a test. A test beyond
+    // that range would drive the idle code, get silently discarded by
+    // `decode_test_index`, and hang the run until `report_count` never
+    // reaches `expected_test_count`.
+    if expected_test_count > MAX_TEST_COUNT {
+        bail!(
+            "{expected_test_count} tests exceeds the {MAX_TEST_COUNT} tests representable on \
+             D0..D{} of the logic port (the all-high code is reserved for \"idle\")",
+            INDEX_PIN_COUNT - 1
+        );
+    }
+
+    // Load and validate `--budget-file`/`--baseline` before spending time on
+    // the measurement run itself, so a missing or malformed file fails fast
+    // instead of discarding a completed run's data at the very end.
+    let budget_config = load_budget_config(&args)?;
 
     let ppk2_port = match args.serial_port {
         Some(p) => p,
@@ -92,43 +171,128 @@ fn main() -> Result<()> {
     flash_firmware(&mut session, &args.elf)?;
 
     // Halt core
-    let mut core = session.core(0)?;
-    core.reset_and_halt(Duration::from_secs(60))?;
+    session.core(0)?.reset_and_halt(Duration::from_secs(60))?;
+
+    // Shared with the defmt/RTT reader thread below: it needs a `Core` to
+    // poll the RTT channel while the main loop still needs one to reset and
+    // (eventually) release the chip.
+    let session = Arc::new(Mutex::new(session));
+    let elf_bytes = std::fs::read(&args.elf)?;
 
     // TODO power off
     // TODO disconnect debugger somehow
     // TODO power on
 
-    // Start measuring, ignoring data if D0 has not been high yet, or if it is high
-    let mut levels = [PinLevel::Either; 8];
-    levels[0] = PinLevel::Low;
-    let pins = LogicPortPins::with_levels(levels);
-    let (rx, kill) = ppk2.start_measurement_matching(pins, args.sps)?;
+    // --capture wants to see transients averaging would hide, so sample as
+    // fast as the PPK2 allows rather than at the nominal `--sps`.
+    let effective_sps = if args.capture.is_some() {
+        SPS_MAX
+    } else {
+        args.sps
+    };
+
+    // Measure every sample; which test (if any) it belongs to is decoded from
+    // the logic port bits the firmware drives, rather than filtered for here.
+    let pins = LogicPortPins::with_levels([PinLevel::Either; 8]);
+    let (rx, kill) = ppk2.start_measurement_matching(pins, effective_sps)?;
+
+    // Writer thread for `--capture`: it owns the output file so disk I/O
+    // never stalls the measurement matcher below. The (sender, handle) pair
+    // is shared with the SIGINT handler below, so a Ctrl-C flushes the
+    // capture file instead of losing its still-buffered tail to
+    // `process::exit`.
+    let capture = args.capture.as_deref().map(capture::spawn).transpose()?;
+    let capture_tx = capture.as_ref().map(|(tx, _)| tx.clone());
+    let capture_shutdown = Arc::new(Mutex::new(capture));
+    let mut capture_elapsed_secs = 0f32;
 
     // Setup signal handler, stopping measurements on SIGKILL
     let kill = Arc::new(Mutex::new(Some(kill)));
     let kill_in_handler = kill.clone();
+    let capture_shutdown_in_handler = capture_shutdown.clone();
     ctrlc::set_handler(move || {
         let mut ppk2 = kill_in_handler.lock().unwrap().take().unwrap()().unwrap();
         ppk2.set_device_power(DevicePower::Disabled).unwrap();
+        if let Some((tx, handle)) = capture_shutdown_in_handler.lock().unwrap().take() {
+            drop(tx);
+            let _ = handle.join();
+        }
         std::process::exit(0);
     })?;
 
-    // Whether a preamble has been detected this run. The preamble
-    // is a state where the port state does not match, that is, D0 is high.
-    // This state must be detected before reporting starts, in order for the device
-    // to get ready for testing.
-    let mut preamble_detected = false;
-    // The current reports cumulative current
-    let mut sum = 0f32;
-    // The number of measurements done in this report, used to calculate the average
-    let mut count = 0;
+    // The PPK2 always samples at 100 kHz internally; `--sps` is achieved by
+    // averaging that many native samples together, and the crate warns the
+    // resulting rate is approximate. Recover the real averaging factor so the
+    // charge integration below uses the true inter-sample interval rather
+    // than the nominal `--sps`. The factor rounds to 1 at rates close to
+    // `SPS_MAX` (such as the `SPS_MAX` that `--capture` uses), at which point
+    // the device is still capped at `SPS_MAX` rather than the full 100 kHz
+    // native rate, so
+    // clamp the recovered rate too or dt undercounts by the same margin.
+    let averaging_factor = (PPK2_NATIVE_SPS / effective_sps as f64).round().max(1.0);
+    let native_rate = (PPK2_NATIVE_SPS / averaging_factor).min(SPS_MAX as f64);
+    let sample_interval_secs = (1.0 / native_rate) as f32;
+    let voltage_volts = u16::from(args.voltage) as f32 / 1000.;
+
+    // Cumulative current/charge/sample-count per decoded test index. Keyed by
+    // index rather than by arrival order, so a dropped transition can at
+    // worst merge or discard one report instead of desynchronizing every
+    // report after it.
+    let mut buckets: HashMap<u8, TestBucket> = HashMap::new();
+    // The index the port is currently encoding, i.e. the test whose samples
+    // are being collected right now. `None` while idle.
+    let mut active_index: Option<u8> = None;
     // The number of reports that have finished this run.
     let mut report_count = 0;
-    // Reset core in order to start tests
-    core.reset()?;
+    // Test names seen via `running \`<name>\`` RTT frames, queued in
+    // firmware execution order so the next bucket to open can claim one.
+    let mut pending_names: VecDeque<String> = VecDeque::new();
+    // Whether the suite has reported an overall result yet: `Some(true)` once
+    // "all tests passed!" is seen, `Some(false)` on a decoded panic, `None`
+    // until either arrives. `defmt_test` has no per-test pass/fail line, so
+    // this is the only pass/fail signal available and applies to every test.
+    let mut suite_passed: Option<bool> = None;
+    // Closed-out test reports, collected for the `--format`/`--output` report.
+    let mut test_summaries: Vec<TestSummary> = Vec::new();
+    // Successive idle (all-high) readings seen in a row since the last
+    // non-idle reading. A single glitched or dropped sample that
+    // decodes as idle mid-test would otherwise split one test's samples into
+    // two reports (and could end the run early via `report_count`), so a
+    // bucket is only closed once idle has been seen this many times running.
+    const IDLE_DEBOUNCE_SAMPLES: u32 = 3;
+    let mut idle_streak = 0u32;
+
+    // Reset core in order to start tests. The RTT reader is spawned only
+    // now, not before: spawning it earlier races `Rtt::attach` against the
+    // firmware's RTT control block, which does not exist until the firmware
+    // (started by this reset) has run far enough to initialize it.
+    session.lock().unwrap().core(0)?.reset()?;
+    let test_events = defmt_stream::spawn(session.clone(), elf_bytes)?;
 
     let ppk2 = loop {
+        // Fold in any test lifecycle events decoded from RTT since the last
+        // iteration, without blocking on them.
+        while let Ok(event) = test_events.try_recv() {
+            match event {
+                TestEvent::Started { test_name } => pending_names.push_back(test_name),
+                TestEvent::AllPassed => {
+                    info!("All tests passed!");
+                    suite_passed = Some(true);
+                }
+                TestEvent::Panicked { message } => {
+                    warn!("Firmware panicked: {message}");
+                    suite_passed = Some(false);
+                }
+            }
+            // The result just arrived, so retroactively apply it to any
+            // bucket that already closed before we saw it.
+            if let Some(passed) = suite_passed {
+                for summary in &mut test_summaries {
+                    summary.passed.get_or_insert(passed);
+                }
+            }
+        }
+
         let rcv_res = rx.recv_timeout(Duration::from_millis(2000));
         if report_count >= expected_test_count {
             // The expected number of tests have ran and have been reported.
@@ -136,35 +300,82 @@ fn main() -> Result<()> {
         }
         use MeasurementMatch::*;
         match rcv_res {
-            // Measurement digital port state matched, add data to current report
-            Ok(Match(Measurement { micro_amps, pins })) if preamble_detected => {
-                count += 1;
-                sum += micro_amps;
+            // A sample arrived; decode which test (if any) it belongs to.
+            Ok(Match(Measurement { micro_amps, pins })) => {
+                capture_elapsed_secs += sample_interval_secs;
+                if let Some(capture_tx) = &capture_tx {
+                    let _ = capture_tx.send(CaptureSample {
+                        timestamp_secs: capture_elapsed_secs,
+                        micro_amps,
+                        pins,
+                    });
+                }
+                let index = decode_test_index(pins);
+                match index {
+                    Some(_) => idle_streak = 0,
+                    None => idle_streak += 1,
+                }
+                // Only trust a reading of idle once it has repeated
+                // `IDLE_DEBOUNCE_SAMPLES` times in a row; until then, treat it
+                // as noise and keep filling whichever bucket is active. A
+                // transition to a different test's index is trusted
+                // immediately, since that's the expected, un-glitched case.
+                let settled_index = if index.is_none() && idle_streak < IDLE_DEBOUNCE_SAMPLES {
+                    active_index
+                } else {
+                    index
+                };
+                if settled_index != active_index {
+                    // The decoded index changed, so the test it names has
+                    // finished: close out and report the bucket we were
+                    // filling, if there was one.
+                    if let Some(prev) = active_index {
+                        if let Some(bucket) = buckets.remove(&prev) {
+                            report_count += 1;
+                            let name = bucket
+                                .name
+                                .clone()
+                                .unwrap_or_else(|| format!("test {prev}"));
+                            // `defmt_test` only reports the suite's overall
+                            // result once every test has run, so a per-test
+                            // status here would misleadingly read "unknown"
+                            // for every test; leave it out of the live line
+                            // and let `suite_passed`'s own log line (above)
+                            // and the JSON/CSV report carry the result.
+                            info!(
+                                "{name}: {:.8} mA avg, {:.4} µC, {:.4} µJ",
+                                bucket.mean_micro_amps() / 1000.,
+                                bucket.charge_micro_coulombs,
+                                bucket.charge_micro_coulombs * voltage_volts
+                            );
+                            test_summaries.push(TestSummary {
+                                index: prev,
+                                name,
+                                passed: suite_passed,
+                                sample_count: bucket.count,
+                                mean_micro_amps: bucket.mean_micro_amps(),
+                                min_micro_amps: bucket.min_micro_amps,
+                                max_micro_amps: bucket.max_micro_amps,
+                                stddev_micro_amps: bucket.stddev_micro_amps(),
+                                charge_micro_coulombs: bucket.charge_micro_coulombs,
+                                energy_micro_joules: bucket.charge_micro_coulombs * voltage_volts,
+                            });
+                        }
+                    }
+                    active_index = settled_index;
+                }
+                if let Some(index) = settled_index {
+                    let bucket = buckets.entry(index).or_default();
+                    if bucket.name.is_none() {
+                        bucket.name = pending_names.pop_front();
+                    }
+                    bucket.add(micro_amps, sample_interval_secs);
+                }
                 trace!("Last: {:.4} mA. Bits: {:?}", micro_amps / 1000., pins);
             }
-            // Digital port state does not match requirements, so either:
-            // - No test has started yet. We mark the preample having been detected,
-            //   so the next match is detected as a test being run, and data collection will start
-            // - The last test has ended, and we report its average current use. The next time the
-            //   port state matches, a new report is set up.
             Ok(NoMatch) => {
-                preamble_detected = true;
-                if count > 0 {
-                    // 7. Report average current use for each test measurement
-                    report_count += 1;
-                    info!(
-                        "Average current for report {report_count}: {:.8} mA",
-                        (sum / count as f32) / 1000.
-                    )
-                }
-                count = 0;
-                sum = 0.;
                 trace!("No match, ignoring.");
             }
-            // We got a match, but no preamble yet.
-            Ok(m) => {
-                trace!("No preamble detected yet {m:?}");
-            }
             // The sender was closed, so we run the kill function.
             Err(RecvTimeoutError::Disconnected) => {
                 break kill.lock().unwrap().take().map(|k| k()).unwrap()
@@ -180,10 +391,305 @@ fn main() -> Result<()> {
         // Power off
         ppk2.set_device_power(DevicePower::Disabled)?;
     }
+
+    // Drop both senders so the capture writer thread's channel closes, then
+    // wait for it to flush the file before we report results.
+    drop(capture_tx);
+    if let Some((tx, handle)) = capture_shutdown.lock().unwrap().take() {
+        drop(tx);
+        handle.join().expect("capture writer thread panicked")?;
+    }
+
+    let report = RunReport {
+        elf: args.elf.clone(),
+        chip: args.chip.clone(),
+        voltage_mv: u16::from(args.voltage),
+        mode: format!("{:?}", args.mode),
+        sps: effective_sps,
+        timestamp_unix: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+        tests: test_summaries,
+    };
+    if !matches!(args.format, OutputFormat::Text) {
+        write_report(&args, &report)?;
+    }
+
+    let violations = check_budget(&args, &report, &budget_config);
+    for violation in &violations {
+        error!("{violation}");
+    }
+
     info!("Goodbye!");
+    if !violations.is_empty() {
+        bail!("{} test(s) failed the power budget", violations.len());
+    }
     Ok(())
 }
 
+/// Budgets and baseline for `check_budget`, loaded from `--budget-file` and
+/// `--baseline` once at startup rather than after the run completes, so a
+/// missing or malformed file fails fast instead of discarding a finished
+/// measurement run.
+struct BudgetConfig {
+    per_test_budgets: HashMap<String, f32>,
+    baseline: Option<RunReport>,
+}
+
+/// Load and parse `--budget-file`/`--baseline`, if given.
+fn load_budget_config(args: &Args) -> Result<BudgetConfig> {
+    let per_test_budgets = match &args.budget_file {
+        Some(path) => serde_json::from_reader(std::fs::File::open(path)?)?,
+        None => HashMap::new(),
+    };
+    let baseline = match &args.baseline {
+        Some(path) => Some(serde_json::from_reader(std::fs::File::open(path)?)?),
+        None => None,
+    };
+    Ok(BudgetConfig {
+        per_test_budgets,
+        baseline,
+    })
+}
+
+/// Compare `report` against `--budget`/`--budget-file` and `--baseline`,
+/// returning one human-readable violation message per test that exceeded its
+/// budget or regressed beyond `--regression-threshold`.
+fn check_budget(args: &Args, report: &RunReport, budget_config: &BudgetConfig) -> Vec<String> {
+    let mut violations = Vec::new();
+    for test in &report.tests {
+        let budget = budget_config
+            .per_test_budgets
+            .get(&test.name)
+            .copied()
+            .or(args.budget);
+        if let Some(budget) = budget {
+            if test.mean_micro_amps > budget {
+                violations.push(format!(
+                    "{}: mean current {:.2} µA exceeds budget of {:.2} µA",
+                    test.name, test.mean_micro_amps, budget
+                ));
+            }
+        }
+
+        let Some(baseline_test) = budget_config
+            .baseline
+            .as_ref()
+            .and_then(|b| b.tests.iter().find(|t| t.name == test.name))
+        else {
+            continue;
+        };
+        if let Some(violation) = regression_violation(test, baseline_test, args.regression_threshold) {
+            violations.push(violation);
+        }
+    }
+    violations
+}
+
+/// Compare `test` against `baseline_test`, returning a violation message if
+/// its mean current regressed beyond `regression_threshold` percent. A
+/// baseline of exactly `0.0 µA` is guarded explicitly: the percentage formula
+/// divides by the baseline mean, so without this guard a zero baseline
+/// yields `inf` (reported as an "inf%" regression) for a positive mean, and
+/// `NaN` or `-inf` otherwise.
+fn regression_violation(
+    test: &TestSummary,
+    baseline_test: &TestSummary,
+    regression_threshold: f32,
+) -> Option<String> {
+    if baseline_test.mean_micro_amps == 0. {
+        return (test.mean_micro_amps > 0.).then(|| {
+            format!(
+                "{}: mean current regressed from a 0.00 µA baseline to {:.2} µA",
+                test.name, test.mean_micro_amps
+            )
+        });
+    }
+    let regression_percent = (test.mean_micro_amps - baseline_test.mean_micro_amps)
+        / baseline_test.mean_micro_amps
+        * 100.;
+    (regression_percent > regression_threshold).then(|| {
+        format!(
+            "{}: mean current regressed {:.1}% vs baseline ({:.2} -> {:.2} µA)",
+            test.name, regression_percent, baseline_test.mean_micro_amps, test.mean_micro_amps
+        )
+    })
+}
+
+/// The number of logic port pins (starting at D0) the firmware uses to encode
+/// the running test's index. Three pins give 8 codes, one of which (all-high)
+/// is reserved to mean "between tests", leaving [`MAX_TEST_COUNT`] for tests.
+const INDEX_PIN_COUNT: usize = 3;
+
+/// The number of test indices (`0..MAX_TEST_COUNT`) the logic port can
+/// represent with [`INDEX_PIN_COUNT`] pins, after reserving the all-high code
+/// (equal to `MAX_TEST_COUNT`) for idle.
+const MAX_TEST_COUNT: usize = (1 << INDEX_PIN_COUNT) - 1;
+
+/// The PPK2's native sample rate in Hz. `--sps` is achieved by averaging this
+/// many samples together, not by sampling at `--sps` directly.
+const PPK2_NATIVE_SPS: f64 = 100_000.;
+
+/// Cumulative current, charge and sample count for a single test, used to
+/// compute its mean current, total charge and energy once its bucket closes.
+struct TestBucket {
+    sum_micro_amps: f32,
+    sum_sq_micro_amps: f32,
+    min_micro_amps: f32,
+    max_micro_amps: f32,
+    count: usize,
+    charge_micro_coulombs: f32,
+    last_micro_amps: Option<f32>,
+    /// The test function name, as decoded from the defmt/RTT stream. `None`
+    /// if no matching `TestEvent::Started` frame had arrived yet when this
+    /// bucket opened.
+    name: Option<String>,
+}
+
+impl Default for TestBucket {
+    fn default() -> Self {
+        Self {
+            sum_micro_amps: 0.,
+            sum_sq_micro_amps: 0.,
+            min_micro_amps: f32::INFINITY,
+            max_micro_amps: f32::NEG_INFINITY,
+            count: 0,
+            charge_micro_coulombs: 0.,
+            last_micro_amps: None,
+            name: None,
+        }
+    }
+}
+
+impl TestBucket {
+    /// Fold in a new sample, integrating charge via the trapezoidal rule
+    /// against the previous sample in this bucket using the true
+    /// inter-sample interval `dt_secs`.
+    fn add(&mut self, micro_amps: f32, dt_secs: f32) {
+        if let Some(prev) = self.last_micro_amps {
+            self.charge_micro_coulombs += 0.5 * (prev + micro_amps) * dt_secs;
+        }
+        self.last_micro_amps = Some(micro_amps);
+        self.sum_micro_amps += micro_amps;
+        self.sum_sq_micro_amps += micro_amps * micro_amps;
+        self.min_micro_amps = self.min_micro_amps.min(micro_amps);
+        self.max_micro_amps = self.max_micro_amps.max(micro_amps);
+        self.count += 1;
+    }
+
+    fn mean_micro_amps(&self) -> f32 {
+        self.sum_micro_amps / self.count as f32
+    }
+
+    fn stddev_micro_amps(&self) -> f32 {
+        let mean = self.mean_micro_amps();
+        (self.sum_sq_micro_amps / self.count as f32 - mean * mean)
+            .max(0.)
+            .sqrt()
+    }
+}
+
+/// A single test's result, as recorded into the `--format`/`--output` report.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct TestSummary {
+    index: u8,
+    name: String,
+    passed: Option<bool>,
+    sample_count: usize,
+    mean_micro_amps: f32,
+    min_micro_amps: f32,
+    max_micro_amps: f32,
+    stddev_micro_amps: f32,
+    charge_micro_coulombs: f32,
+    energy_micro_joules: f32,
+}
+
+/// The full machine-readable report for a run: metadata about how it was
+/// taken, plus every test's result. Written at the end of the run when
+/// `--format` requests `json` or `csv`, so results can be archived and
+/// diffed across commits in CI.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RunReport {
+    elf: PathBuf,
+    chip: String,
+    voltage_mv: u16,
+    mode: String,
+    sps: usize,
+    timestamp_unix: u64,
+    tests: Vec<TestSummary>,
+}
+
+/// A single CSV row, combining the run metadata with one test's result since
+/// CSV has no native way to nest the two.
+#[derive(serde::Serialize)]
+struct CsvRow<'a> {
+    elf: &'a Path,
+    chip: &'a str,
+    voltage_mv: u16,
+    mode: &'a str,
+    sps: usize,
+    timestamp_unix: u64,
+    index: u8,
+    name: &'a str,
+    passed: Option<bool>,
+    sample_count: usize,
+    mean_micro_amps: f32,
+    min_micro_amps: f32,
+    max_micro_amps: f32,
+    stddev_micro_amps: f32,
+    charge_micro_coulombs: f32,
+    energy_micro_joules: f32,
+}
+
+/// Write `report` to `args.output` (or stdout, if unset) in `args.format`.
+fn write_report(args: &Args, report: &RunReport) -> Result<()> {
+    let mut writer: Box<dyn std::io::Write> = match &args.output {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+    match args.format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => serde_json::to_writer_pretty(writer, report)?,
+        OutputFormat::Csv => {
+            let mut csv = csv::Writer::from_writer(&mut writer);
+            for test in &report.tests {
+                csv.serialize(CsvRow {
+                    elf: &report.elf,
+                    chip: &report.chip,
+                    voltage_mv: report.voltage_mv,
+                    mode: &report.mode,
+                    sps: report.sps,
+                    timestamp_unix: report.timestamp_unix,
+                    index: test.index,
+                    name: &test.name,
+                    passed: test.passed,
+                    sample_count: test.sample_count,
+                    mean_micro_amps: test.mean_micro_amps,
+                    min_micro_amps: test.min_micro_amps,
+                    max_micro_amps: test.max_micro_amps,
+                    stddev_micro_amps: test.stddev_micro_amps,
+                    charge_micro_coulombs: test.charge_micro_coulombs,
+                    energy_micro_joules: test.energy_micro_joules,
+                })?;
+            }
+            csv.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Decode the test index the firmware drives onto D0..D(INDEX_PIN_COUNT - 1)
+/// of the logic port in its `before_each`/`after_each` hooks. Returns `None`
+/// for the reserved all-high idle code, which means no test is running and
+/// the sample should be discarded.
+fn decode_test_index(pins: LogicPortPins) -> Option<u8> {
+    let levels = pins.to_levels();
+    let index = (0..INDEX_PIN_COUNT).fold(0u8, |index, bit| {
+        index | ((levels[bit] == PinLevel::High) as u8) << bit
+    });
+    (index as usize != MAX_TEST_COUNT).then_some(index)
+}
+
 /// Read the number of tests the device will run from the ELF.
 /// This function assumes [defmt-test] is used to set up the test binary,
 /// as it uses the `DEFMT_TEST_COUNT` symbol value exposed in the ELF.
@@ -250,3 +756,105 @@ fn flash_firmware(session: &mut Session, elf: impl AsRef<Path>) -> Result<()> {
     info!("Done!");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_tracks_mean_min_max_and_charge() {
+        let mut bucket = TestBucket::default();
+        bucket.add(10., 1.);
+        bucket.add(20., 1.);
+        assert_eq!(bucket.count, 2);
+        assert_eq!(bucket.mean_micro_amps(), 15.);
+        assert_eq!(bucket.min_micro_amps, 10.);
+        assert_eq!(bucket.max_micro_amps, 20.);
+        // Trapezoidal rule over one 1s step between 10 and 20 µA.
+        assert_eq!(bucket.charge_micro_coulombs, 15.);
+    }
+
+    #[test]
+    fn bucket_stddev_is_zero_for_constant_current() {
+        let mut bucket = TestBucket::default();
+        bucket.add(10., 1.);
+        bucket.add(10., 1.);
+        assert_eq!(bucket.stddev_micro_amps(), 0.);
+    }
+
+    #[test]
+    fn empty_bucket_defaults_leave_min_above_max() {
+        let bucket = TestBucket::default();
+        assert!(bucket.min_micro_amps > bucket.max_micro_amps);
+        assert_eq!(bucket.count, 0);
+    }
+
+    fn pins_for_index(index: u8) -> LogicPortPins {
+        let mut levels = [PinLevel::Low; 8];
+        for bit in 0..INDEX_PIN_COUNT {
+            levels[bit] = if index & (1 << bit) != 0 {
+                PinLevel::High
+            } else {
+                PinLevel::Low
+            };
+        }
+        LogicPortPins::with_levels(levels)
+    }
+
+    #[test]
+    fn decodes_test_index_from_low_pins() {
+        assert_eq!(decode_test_index(pins_for_index(0)), Some(0));
+        assert_eq!(decode_test_index(pins_for_index(5)), Some(5));
+        assert_eq!(decode_test_index(pins_for_index(MAX_TEST_COUNT as u8 - 1)), Some(MAX_TEST_COUNT as u8 - 1));
+    }
+
+    #[test]
+    fn all_high_index_pins_decode_as_idle() {
+        assert_eq!(decode_test_index(pins_for_index(MAX_TEST_COUNT as u8)), None);
+    }
+
+    fn summary(name: &str, mean_micro_amps: f32) -> TestSummary {
+        TestSummary {
+            index: 0,
+            name: name.to_string(),
+            passed: None,
+            sample_count: 1,
+            mean_micro_amps,
+            min_micro_amps: mean_micro_amps,
+            max_micro_amps: mean_micro_amps,
+            stddev_micro_amps: 0.,
+            charge_micro_coulombs: 0.,
+            energy_micro_joules: 0.,
+        }
+    }
+
+    #[test]
+    fn flags_regression_past_threshold() {
+        let test = summary("it_works", 110.);
+        let baseline = summary("it_works", 100.);
+        let violation = regression_violation(&test, &baseline, 5.).unwrap();
+        assert!(violation.contains("regressed 10.0%"));
+    }
+
+    #[test]
+    fn ignores_regression_within_threshold() {
+        let test = summary("it_works", 102.);
+        let baseline = summary("it_works", 100.);
+        assert!(regression_violation(&test, &baseline, 5.).is_none());
+    }
+
+    #[test]
+    fn flags_nonzero_mean_against_zero_baseline() {
+        let test = summary("it_works", 5.);
+        let baseline = summary("it_works", 0.);
+        let violation = regression_violation(&test, &baseline, 5.).unwrap();
+        assert!(violation.contains("0.00 µA baseline"));
+    }
+
+    #[test]
+    fn zero_baseline_and_zero_mean_is_not_a_regression() {
+        let test = summary("it_works", 0.);
+        let baseline = summary("it_works", 0.);
+        assert!(regression_violation(&test, &baseline, 5.).is_none());
+    }
+}